@@ -0,0 +1,85 @@
+//! Thin seam around the `wgpu::` instance/adapter/device/swap-chain calls
+//! whose behavior depends on [`crate::RendererConfig`]. Keeping them here
+//! means backend choice (Vulkan vs GL vs a software fallback) is made in
+//! one place, instead of being hard-coded at every call site, and gives
+//! future alternative WebGPU implementations a single seam to target.
+
+use crate::RendererConfig;
+use wgpu::{Adapter, Device, Instance, Queue, Surface, SwapChain, SwapChainDescriptor};
+
+pub(crate) fn create_instance(config: &RendererConfig) -> Instance {
+    Instance::new(config.backends)
+}
+
+pub unsafe fn create_surface(instance: &Instance, window: &winit::window::Window) -> Surface {
+    instance.create_surface(window)
+}
+
+// wgpu 0.6's `RequestAdapterOptions` predates `force_fallback_adapter` (that
+// request knob landed in a later wgpu release), so there's no way to ask
+// this version for a software adapter directly. `config.force_fallback` is
+// kept on `RendererConfig` anyway; `request_device` below only logs it, and
+// it changes neither the adapter chosen here nor the limits requested there.
+pub(crate) async fn request_adapter(
+    instance: &Instance,
+    config: &RendererConfig,
+    compatible_surface: Option<&Surface>,
+) -> Adapter {
+    instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: config.power_preference,
+            compatible_surface,
+        })
+        .await
+        .expect("Failed to find an appropriate adapter")
+}
+
+pub(crate) async fn request_device(adapter: &Adapter, config: &RendererConfig) -> (Device, Queue) {
+    // wgpu 0.6 has no `Limits::downlevel_webgl2_defaults()` preset (also a
+    // later-wgpu addition); its `Limits` are already just bind-group/sampler
+    // counts, not texture-size ceilings, so there's nothing to clamp harder
+    // for a fallback adapter. Logged so it's clear the flag was seen even
+    // though this wgpu version can't act on it.
+    println!(
+        "requesting device (force_fallback={})",
+        config.force_fallback
+    );
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::empty(),
+                limits: wgpu::Limits::default(),
+                shader_validation: true,
+            },
+            None,
+        )
+        .await
+        .expect("Failed to create device")
+}
+
+pub fn create_swap_chain(
+    device: &Device,
+    surface: &Surface,
+    config: &RendererConfig,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+) -> (SwapChainDescriptor, SwapChain) {
+    let sc_desc = SwapChainDescriptor {
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        format,
+        width,
+        height,
+        present_mode: config.present_mode,
+    };
+    let swap_chain = device.create_swap_chain(surface, &sc_desc);
+    (sc_desc, swap_chain)
+}
+
+pub fn recreate_swap_chain(
+    device: &Device,
+    surface: &Surface,
+    sc_desc: &SwapChainDescriptor,
+) -> SwapChain {
+    device.create_swap_chain(surface, sc_desc)
+}
@@ -1,9 +1,14 @@
 // use ndk::trace;
+mod gpu;
+
 use async_mutex::Mutex;
+use bytemuck::{Pod, Zeroable};
+use std::borrow::Cow;
 use std::sync::Arc;
+use wgpu::util::DeviceExt;
 use wgpu::{
-    Adapter, Device, Instance, PipelineLayout, Queue, RenderPipeline, ShaderModule, Surface,
-    SwapChain, SwapChainDescriptor,
+    Adapter, Buffer, Device, Instance, PipelineLayout, Queue, RenderPipeline, ShaderModule,
+    Surface, SwapChain, SwapChainDescriptor,
 };
 use winit::{
     event::{Event, StartCause, WindowEvent},
@@ -11,39 +16,431 @@ use winit::{
     window::Window,
 };
 
+/// A single mesh vertex: position and a flat color, matching the
+/// `location(0)`/`location(1)` attributes consumed by `vs_main` in
+/// [`DEFAULT_WGSL_SHADER`].
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+impl Vertex {
+    const ATTRIBS: [wgpu::VertexAttributeDescriptor; 2] = [
+        wgpu::VertexAttributeDescriptor {
+            offset: 0,
+            shader_location: 0,
+            format: wgpu::VertexFormat::Float3,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+            shader_location: 1,
+            format: wgpu::VertexFormat::Float3,
+        },
+    ];
+
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+/// A single draw instance: a 4x4 model matrix, uploaded as four `vec4`
+/// attributes (`location(2..=5)`) since WGSL vertex inputs can't be a
+/// bare matrix.
+///
+/// Named `InstanceRaw` rather than `Instance` so it doesn't collide with
+/// `wgpu::Instance` (the GPU/backend handle) in this module's namespace.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct InstanceRaw {
+    pub model: [[f32; 4]; 4],
+}
+
+impl InstanceRaw {
+    const ATTRIBS: [wgpu::VertexAttributeDescriptor; 4] = [
+        wgpu::VertexAttributeDescriptor {
+            offset: 0,
+            shader_location: 2,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+            shader_location: 3,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: (std::mem::size_of::<[f32; 4]>() * 2) as wgpu::BufferAddress,
+            shader_location: 4,
+            format: wgpu::VertexFormat::Float4,
+        },
+        wgpu::VertexAttributeDescriptor {
+            offset: (std::mem::size_of::<[f32; 4]>() * 3) as wgpu::BufferAddress,
+            shader_location: 5,
+            format: wgpu::VertexFormat::Float4,
+        },
+    ];
+
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+
+    fn identity() -> Self {
+        InstanceRaw {
+            model: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+}
+
+/// The demo triangle, now driven through a real vertex buffer instead of
+/// `gl_VertexIndex`.
+const TRIANGLE_VERTICES: &[Vertex] = &[
+    Vertex {
+        position: [0.0, 0.5, 0.0],
+        color: [1.0, 0.0, 0.0],
+    },
+    Vertex {
+        position: [-0.5, -0.5, 0.0],
+        color: [0.0, 1.0, 0.0],
+    },
+    Vertex {
+        position: [0.5, -0.5, 0.0],
+        color: [0.0, 0.0, 1.0],
+    },
+];
+
+/// Default WGSL shader used when the caller doesn't supply one: a single
+/// `vs_main`/`fs_main` pair standing in for the old `shader.vert.spv` /
+/// `shader.frag.spv` pair.
+#[cfg(not(feature = "spirv-shaders"))]
+pub const DEFAULT_WGSL_SHADER: &str = r#"
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] color: vec3<f32>;
+};
+
+struct InstanceInput {
+    [[location(2)]] model_matrix_0: vec4<f32>;
+    [[location(3)]] model_matrix_1: vec4<f32>;
+    [[location(4)]] model_matrix_2: vec4<f32>;
+    [[location(5)]] model_matrix_3: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] color: vec3<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: VertexOutput;
+    out.color = model.color;
+    out.clip_position = model_matrix * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return vec4<f32>(in.color, 1.0);
+}
+"#;
+
+/// Backend choices that used to be hard-coded (`BackendBit::PRIMARY`,
+/// `PowerPreference::Default`, ...). Threaded into `run` and on into
+/// `setup`, so callers can steer toward Vulkan vs GL via `backends`, which
+/// varies a lot across Android devices, desktop, and web.
+#[derive(Debug, Clone, Copy)]
+pub struct RendererConfig {
+    pub backends: wgpu::BackendBit,
+    pub power_preference: wgpu::PowerPreference,
+    /// Only logged when the device is requested; it does not select a
+    /// software fallback adapter or change the requested limits.
+    pub force_fallback: bool,
+    pub present_mode: wgpu::PresentMode,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            backends: wgpu::BackendBit::PRIMARY,
+            power_preference: wgpu::PowerPreference::Default,
+            force_fallback: false,
+            present_mode: wgpu::PresentMode::Mailbox,
+        }
+    }
+}
+
 pub struct WgpuContext {
     instance: Instance,
     stage: WgpuStage,
+    config: RendererConfig,
+    /// WGSL source compiled by `setup` in place of the baked-in SPIR-V
+    /// modules. Unused when the `spirv-shaders` feature is enabled.
+    #[cfg(not(feature = "spirv-shaders"))]
+    wgsl_source: Cow<'static, str>,
 }
 
 pub enum WgpuStage {
     Init,
-    Ready(InnerContext),
+    Ready(Box<InnerContext>),
 }
 
 impl WgpuStage {
     pub fn not_ready(&self) -> bool {
-        match self {
-            WgpuStage::Ready(_) => false,
-            _ => true,
-        }
+        !matches!(self, WgpuStage::Ready(_))
     }
 }
 
 pub struct InnerContext {
     surface: Option<Surface>,
+    // Never read again after setup, but must outlive the resources created
+    // from it (device/queue, shader modules, pipeline layout), so it's kept
+    // here rather than dropped.
+    #[allow(dead_code)]
     adapter: Adapter,
     device: Device,
     queue: Queue,
+    // Kept alive for the render pipeline compiled from them; never read
+    // again once the pipeline exists.
+    #[cfg(feature = "spirv-shaders")]
+    #[allow(dead_code)]
     vs_module: ShaderModule,
+    #[cfg(feature = "spirv-shaders")]
+    #[allow(dead_code)]
     fs_module: ShaderModule,
+    // Kept alive for the render pipeline it was compiled into; never read
+    // again once the pipeline exists.
+    #[cfg(not(feature = "spirv-shaders"))]
+    #[allow(dead_code)]
+    shader_module: ShaderModule,
+    // Kept alive for the render pipeline built from it; never read again.
+    #[allow(dead_code)]
     pipeline_layout: PipelineLayout,
     render_pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    num_vertices: u32,
+    instance_buffer: Buffer,
+    num_instances: u32,
     sc_desc: SwapChainDescriptor,
-    swap_chain: SwapChain,
+    color_format: wgpu::TextureFormat,
+    render_target: RenderTarget,
+}
+
+/// Where a rendered frame ends up. The live window path presents through a
+/// `SwapChain` as before; `TextureTarget` renders into an offscreen texture
+/// and a matching readback buffer so `capture_frame` can pull pixels out
+/// without ever opening a window (headless rendering / screenshot tests).
+pub enum RenderTarget {
+    SwapChainTarget(SwapChain),
+    TextureTarget {
+        texture: wgpu::Texture,
+        view: wgpu::TextureView,
+        buffer: wgpu::Buffer,
+    },
+}
+
+/// Bytes-per-row in a `wgpu` buffer copy must be a multiple of
+/// `COPY_BYTES_PER_ROW_ALIGNMENT`; this rounds a tightly packed RGBA row up
+/// to that alignment.
+fn padded_bytes_per_row(width: u32) -> u32 {
+    let bytes_per_pixel = std::mem::size_of::<u32>() as u32;
+    let unpadded = width * bytes_per_pixel;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    unpadded + (align - unpadded % align) % align
+}
+
+impl RenderTarget {
+    fn new_texture_target(
+        device: &Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> RenderTarget {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen render target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let padded_row_size = padded_bytes_per_row(width) as wgpu::BufferAddress;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("frame capture buffer"),
+            size: padded_row_size * height as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        RenderTarget::TextureTarget {
+            texture,
+            view,
+            buffer,
+        }
+    }
+}
+
+/// Holds whichever frame the active `RenderTarget` handed us just long
+/// enough to run a render pass against it: a `SwapChainFrame` presents on
+/// drop, while the offscreen texture's view is simply borrowed.
+enum FrameHandle<'a> {
+    SwapChain(wgpu::SwapChainFrame),
+    Texture(&'a wgpu::TextureView),
+}
+
+impl<'a> FrameHandle<'a> {
+    fn view(&self) -> &wgpu::TextureView {
+        match self {
+            FrameHandle::SwapChain(frame) => &frame.output.view,
+            FrameHandle::Texture(view) => view,
+        }
+    }
+}
+
+/// The shader modules, pipeline layout, render pipeline, and demo
+/// vertex/instance buffers, bundled up so [`build_pipeline`] has a single
+/// return type instead of a growing tuple.
+struct PipelineBundle {
+    #[cfg(feature = "spirv-shaders")]
+    vs_module: ShaderModule,
+    #[cfg(feature = "spirv-shaders")]
+    fs_module: ShaderModule,
+    #[cfg(not(feature = "spirv-shaders"))]
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
+    render_pipeline: RenderPipeline,
+    vertex_buffer: Buffer,
+    num_vertices: u32,
+    instance_buffer: Buffer,
+    num_instances: u32,
+}
+
+/// Compiles the shaders, builds the pipeline layout and render pipeline, and
+/// uploads the demo vertex/instance buffers. Shared by `setup`'s first-time
+/// init path and [`new_headless`] so the two don't keep independent copies
+/// of this that would drift the next time the pipeline changes.
+fn build_pipeline(
+    device: &Device,
+    format: wgpu::TextureFormat,
+    #[cfg(not(feature = "spirv-shaders"))] wgsl_source: &str,
+) -> PipelineBundle {
+    #[cfg(feature = "spirv-shaders")]
+    println!("Device created, loading precompiled SPIR-V shaders");
+    #[cfg(feature = "spirv-shaders")]
+    let vs_module = device.create_shader_module(wgpu::include_spirv!("shader.vert.spv"));
+    #[cfg(feature = "spirv-shaders")]
+    let fs_module = device.create_shader_module(wgpu::include_spirv!("shader.frag.spv"));
+
+    #[cfg(not(feature = "spirv-shaders"))]
+    println!("Device created, compiling WGSL shader");
+    // wgpu 0.6's `create_shader_module` takes a `ShaderModuleSource`
+    // directly; the `ShaderModuleDescriptor { label, source, flags }`
+    // wrapper only exists in later wgpu releases.
+    #[cfg(not(feature = "spirv-shaders"))]
+    let shader_module = device
+        .create_shader_module(wgpu::ShaderModuleSource::Wgsl(Cow::Borrowed(wgsl_source)));
+
+    println!("shaders created, loading pipeline layout");
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: None,
+        bind_group_layouts: &[],
+        push_constant_ranges: &[],
+    });
+
+    let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        #[cfg(feature = "spirv-shaders")]
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: &vs_module,
+            entry_point: "main",
+        },
+        #[cfg(feature = "spirv-shaders")]
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: &fs_module,
+            entry_point: "main",
+        }),
+        #[cfg(not(feature = "spirv-shaders"))]
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: &shader_module,
+            entry_point: "vs_main",
+        },
+        #[cfg(not(feature = "spirv-shaders"))]
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: &shader_module,
+            entry_point: "fs_main",
+        }),
+        // Use the default rasterizer state: no culling, no depth bias
+        rasterization_state: None,
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+        color_states: &[format.into()],
+        depth_stencil_state: None,
+        vertex_state: wgpu::VertexStateDescriptor {
+            index_format: wgpu::IndexFormat::Uint16,
+            vertex_buffers: &[Vertex::desc(), InstanceRaw::desc()],
+        },
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+    });
+
+    let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("vertex buffer"),
+        contents: bytemuck::cast_slice(TRIANGLE_VERTICES),
+        usage: wgpu::BufferUsage::VERTEX,
+    });
+    let num_vertices = TRIANGLE_VERTICES.len() as u32;
+
+    let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("instance buffer"),
+        contents: bytemuck::cast_slice(&[InstanceRaw::identity()]),
+        usage: wgpu::BufferUsage::VERTEX,
+    });
+    let num_instances = 1;
+
+    PipelineBundle {
+        #[cfg(feature = "spirv-shaders")]
+        vs_module,
+        #[cfg(feature = "spirv-shaders")]
+        fs_module,
+        #[cfg(not(feature = "spirv-shaders"))]
+        shader_module,
+        pipeline_layout,
+        render_pipeline,
+        vertex_buffer,
+        num_vertices,
+        instance_buffer,
+        num_instances,
+    }
 }
 
-async fn setup(
+pub async fn setup(
     context: &mut Arc<Mutex<WgpuContext>>,
     window: Arc<Window>,
     swapchain_format: &wgpu::TextureFormat,
@@ -58,104 +455,60 @@ async fn setup(
         "setup start, native_window: {}",
         ndk_glue::native_window().as_ref().is_some()
     );
-    let ref mut ctx = *unlocked_context;
+    let ctx = &mut *unlocked_context;
     match ctx.stage {
         WgpuStage::Init => {
             let surface = if init {
-                Some(unsafe { ctx.instance.create_surface(&*window) })
+                Some(unsafe { gpu::create_surface(&ctx.instance, &window) })
             } else {
                 None
             };
             if let Some(ref s) = surface {
-                let adapter = ctx
-                    .instance
-                    .request_adapter(&wgpu::RequestAdapterOptions {
-                        power_preference: wgpu::PowerPreference::Default,
-                        // Request an adapter which can render to our surface
-                        compatible_surface: Some(s),
-                    })
-                    .await
-                    .expect("Failed to find an appropriate adapter");
+                let adapter = gpu::request_adapter(&ctx.instance, &ctx.config, Some(s)).await;
 
                 println!("Adapter: \t {:?}", adapter.get_info());
-                // Create the logical device and command queue
-                let (device, queue) = adapter
-                    .request_device(
-                        &wgpu::DeviceDescriptor {
-                            features: wgpu::Features::empty(),
-                            limits: wgpu::Limits::default(),
-                            shader_validation: true,
-                        },
-                        None,
-                    )
-                    .await
-                    .expect("Failed to create device");
-
-                // Load the shaders from disk
-                println!("Device created, loading shaders");
-                let vs_module =
-                    device.create_shader_module(wgpu::include_spirv!("shader.vert.spv"));
-                let fs_module =
-                    device.create_shader_module(wgpu::include_spirv!("shader.frag.spv"));
-
-                println!("shaders created, loading pipeline layout");
-                let pipeline_layout =
-                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                        label: None,
-                        bind_group_layouts: &[],
-                        push_constant_ranges: &[],
-                    });
-
-                println!("shaders created, loading pipeline layout");
-                let render_pipeline =
-                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                        label: None,
-                        layout: Some(&pipeline_layout),
-                        vertex_stage: wgpu::ProgrammableStageDescriptor {
-                            module: &vs_module,
-                            entry_point: "main",
-                        },
-                        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                            module: &fs_module,
-                            entry_point: "main",
-                        }),
-                        // Use the default rasterizer state: no culling, no depth bias
-                        rasterization_state: None,
-                        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
-                        color_states: &[(*swapchain_format).into()],
-                        depth_stencil_state: None,
-                        vertex_state: wgpu::VertexStateDescriptor {
-                            index_format: wgpu::IndexFormat::Uint16,
-                            vertex_buffers: &[],
-                        },
-                        sample_count: 1,
-                        sample_mask: !0,
-                        alpha_to_coverage_enabled: false,
-                    });
+                let (device, queue) = gpu::request_device(&adapter, &ctx.config).await;
+
+                let pipeline = build_pipeline(
+                    &device,
+                    *swapchain_format,
+                    #[cfg(not(feature = "spirv-shaders"))]
+                    ctx.wgsl_source.as_ref(),
+                );
 
                 let size = window.inner_size();
-                let sc_desc = wgpu::SwapChainDescriptor {
-                    usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
-                    format: swapchain_format.clone(),
-                    width: size.width,
-                    height: size.height,
-                    present_mode: wgpu::PresentMode::Mailbox,
-                };
+                let (sc_desc, swap_chain) = gpu::create_swap_chain(
+                    &device,
+                    s,
+                    &ctx.config,
+                    *swapchain_format,
+                    size.width,
+                    size.height,
+                );
 
-                let swap_chain = device.create_swap_chain(s, &sc_desc);
+                let render_target = RenderTarget::SwapChainTarget(swap_chain);
 
-                ctx.stage = WgpuStage::Ready(InnerContext {
+                ctx.stage = WgpuStage::Ready(Box::new(InnerContext {
                     surface,
                     adapter,
                     device,
                     queue,
-                    vs_module,
-                    fs_module,
-                    pipeline_layout,
-                    render_pipeline,
+                    #[cfg(feature = "spirv-shaders")]
+                    vs_module: pipeline.vs_module,
+                    #[cfg(feature = "spirv-shaders")]
+                    fs_module: pipeline.fs_module,
+                    #[cfg(not(feature = "spirv-shaders"))]
+                    shader_module: pipeline.shader_module,
+                    pipeline_layout: pipeline.pipeline_layout,
+                    render_pipeline: pipeline.render_pipeline,
+                    vertex_buffer: pipeline.vertex_buffer,
+                    num_vertices: pipeline.num_vertices,
+                    instance_buffer: pipeline.instance_buffer,
+                    num_instances: pipeline.num_instances,
                     sc_desc,
-                    swap_chain,
-                });
+                    color_format: *swapchain_format,
+                    render_target,
+                }));
                 println!("setup ok");
             }
         }
@@ -165,23 +518,28 @@ async fn setup(
             #[cfg(not(target_os = "android"))]
             let init = inner.surface.is_none();
             if init {
-                let surface = if init {
-                    Some(unsafe { ctx.instance.create_surface(&*window) })
-                } else {
-                    None
-                };
+                // Recreate the surface against the retained device/queue/pipelines
+                // and rebuild the swap chain to match, so resuming after
+                // Event::Suspended doesn't leave `draw` pointed at a stale chain.
+                let surface = Some(unsafe { gpu::create_surface(&ctx.instance, &window) });
+                if let Some(ref s) = surface {
+                    inner.render_target =
+                        RenderTarget::SwapChainTarget(gpu::recreate_swap_chain(
+                            &inner.device,
+                            s,
+                            &inner.sc_desc,
+                        ));
+                }
                 inner.surface = surface;
-            } 
-            // else {
-            //     inner.device.
-            // }
+                println!("setup: surface and swap chain recreated after resume");
+            }
         }
     }
 }
 
-async fn clean_surface(context: &mut Arc<Mutex<WgpuContext>>) {
+pub async fn clean_surface(context: &mut Arc<Mutex<WgpuContext>>) {
     let mut unlocked_context = context.lock().await;
-    let ref mut ctx = *unlocked_context;
+    let ctx = &mut *unlocked_context;
     match ctx.stage {
         WgpuStage::Ready(ref mut inner) => {
             let _ = inner.surface.take();
@@ -190,17 +548,34 @@ async fn clean_surface(context: &mut Arc<Mutex<WgpuContext>>) {
     };
 }
 
-async fn draw(context: &mut Arc<Mutex<WgpuContext>>) {
+pub async fn draw(context: &mut Arc<Mutex<WgpuContext>>) {
     println!("draw");
     let mut unlocked_context = context.lock().await;
-    let ref mut ctx = *unlocked_context;
+    let ctx = &mut *unlocked_context;
     match ctx.stage {
         WgpuStage::Ready(ref mut ready) => {
-            let frame = ready
-                .swap_chain
-                .get_current_frame()
-                .expect("Failed to acquire next swap chain texture")
-                .output;
+            if ready.surface.is_none() {
+                println!("draw: no surface (app is backgrounded), skipping frame");
+                return;
+            }
+
+            let frame_handle = match ready.render_target {
+                RenderTarget::SwapChainTarget(ref mut swap_chain) => {
+                    match swap_chain.get_current_frame() {
+                        Ok(frame) => FrameHandle::SwapChain(frame),
+                        Err(wgpu::SwapChainError::Outdated) | Err(wgpu::SwapChainError::Lost) => {
+                            println!("draw: swap chain outdated/lost, rebuilding");
+                            if let Some(ref surface) = ready.surface {
+                                *swap_chain =
+                                    gpu::recreate_swap_chain(&ready.device, surface, &ready.sc_desc);
+                            }
+                            return;
+                        }
+                        Err(e) => panic!("Failed to acquire next swap chain texture: {:?}", e),
+                    }
+                }
+                RenderTarget::TextureTarget { ref view, .. } => FrameHandle::Texture(view),
+            };
 
             let mut encoder = ready
                 .device
@@ -208,7 +583,7 @@ async fn draw(context: &mut Arc<Mutex<WgpuContext>>) {
             {
                 let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                     color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: &frame.view,
+                        attachment: frame_handle.view(),
                         resolve_target: None,
                         ops: wgpu::Operations {
                             load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
@@ -219,7 +594,9 @@ async fn draw(context: &mut Arc<Mutex<WgpuContext>>) {
                 });
 
                 rpass.set_pipeline(&ready.render_pipeline);
-                rpass.draw(0..3, 0..1);
+                rpass.set_vertex_buffer(0, ready.vertex_buffer.slice(..));
+                rpass.set_vertex_buffer(1, ready.instance_buffer.slice(..));
+                rpass.draw(0..ready.num_vertices, 0..ready.num_instances);
             }
 
             ready.queue.submit(Some(encoder.finish()));
@@ -230,11 +607,195 @@ async fn draw(context: &mut Arc<Mutex<WgpuContext>>) {
     }
 }
 
-fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::TextureFormat) {
-    let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+/// Builds a [`WgpuContext`] without ever creating a `winit::window::Window`,
+/// `Surface`, or `SwapChain`: the adapter is requested with no compatible
+/// surface and the pipeline renders straight into a `TextureTarget` of
+/// `width`x`height`. Pair this with [`capture_frame`] to drive
+/// screenshot/CI tests on a headless machine, where `run`/`setup` (which
+/// require a live window to create a surface) can't be used at all.
+pub async fn new_headless(
+    config: RendererConfig,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    #[cfg(not(feature = "spirv-shaders"))] wgsl_source: Cow<'static, str>,
+) -> Arc<Mutex<WgpuContext>> {
+    let instance = gpu::create_instance(&config);
+    let adapter = gpu::request_adapter(&instance, &config, None).await;
+    println!("Adapter (headless): \t {:?}", adapter.get_info());
+    let (device, queue) = gpu::request_device(&adapter, &config).await;
+
+    let pipeline = build_pipeline(
+        &device,
+        format,
+        #[cfg(not(feature = "spirv-shaders"))]
+        wgsl_source.as_ref(),
+    );
+
+    let sc_desc = wgpu::SwapChainDescriptor {
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        format,
+        width,
+        height,
+        present_mode: config.present_mode,
+    };
+    let render_target = RenderTarget::new_texture_target(&device, format, width, height);
+
+    let inner = InnerContext {
+        surface: None,
+        adapter,
+        device,
+        queue,
+        #[cfg(feature = "spirv-shaders")]
+        vs_module: pipeline.vs_module,
+        #[cfg(feature = "spirv-shaders")]
+        fs_module: pipeline.fs_module,
+        #[cfg(not(feature = "spirv-shaders"))]
+        shader_module: pipeline.shader_module,
+        pipeline_layout: pipeline.pipeline_layout,
+        render_pipeline: pipeline.render_pipeline,
+        vertex_buffer: pipeline.vertex_buffer,
+        num_vertices: pipeline.num_vertices,
+        instance_buffer: pipeline.instance_buffer,
+        num_instances: pipeline.num_instances,
+        sc_desc,
+        color_format: format,
+        render_target,
+    };
+
+    Arc::new(Mutex::new(WgpuContext {
+        instance,
+        stage: WgpuStage::Ready(Box::new(inner)),
+        config,
+        #[cfg(not(feature = "spirv-shaders"))]
+        wgsl_source,
+    }))
+}
+
+/// Renders one frame into an offscreen texture and reads it back as tightly
+/// packed RGBA8 bytes. This is the headless counterpart to `draw`: it never
+/// touches the window surface, so it can drive screenshot/CI tests for a
+/// crate that otherwise requires a live window. Call [`new_headless`] to
+/// build the `WgpuContext` this expects; if a `WgpuContext` built by
+/// `setup` is passed in instead, the active `RenderTarget` is switched from
+/// `SwapChainTarget` to `TextureTarget` on first use.
+pub async fn capture_frame(context: &mut Arc<Mutex<WgpuContext>>) -> Vec<u8> {
+    println!("capture_frame");
+    let mut unlocked_context = context.lock().await;
+    let ctx = &mut *unlocked_context;
+    let ready = match ctx.stage {
+        WgpuStage::Ready(ref mut ready) => ready,
+        WgpuStage::Init => panic!("capture_frame called before setup() finished"),
+    };
+
+    let width = ready.sc_desc.width;
+    let height = ready.sc_desc.height;
+    if !matches!(ready.render_target, RenderTarget::TextureTarget { .. }) {
+        ready.render_target =
+            RenderTarget::new_texture_target(&ready.device, ready.color_format, width, height);
+    }
+
+    let (view, texture, buffer) = match &ready.render_target {
+        RenderTarget::TextureTarget {
+            view,
+            texture,
+            buffer,
+        } => (view, texture, buffer),
+        RenderTarget::SwapChainTarget(_) => unreachable!("just switched to a TextureTarget"),
+    };
+
+    let mut encoder = ready
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+    {
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::GREEN),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        rpass.set_pipeline(&ready.render_pipeline);
+        rpass.set_vertex_buffer(0, ready.vertex_buffer.slice(..));
+        rpass.set_vertex_buffer(1, ready.instance_buffer.slice(..));
+        rpass.draw(0..ready.num_vertices, 0..ready.num_instances);
+    }
+
+    let padded_row_size = padded_bytes_per_row(width);
+    encoder.copy_texture_to_buffer(
+        wgpu::TextureCopyView {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::BufferCopyView {
+            buffer,
+            layout: wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: padded_row_size,
+                rows_per_image: height,
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+    );
+
+    ready.queue.submit(Some(encoder.finish()));
+
+    let buffer_slice = buffer.slice(..);
+    let map_future = buffer_slice.map_async(wgpu::MapMode::Read);
+    ready.device.poll(wgpu::Maintain::Wait);
+    map_future.await.expect("failed to map frame capture buffer");
+
+    let bytes_per_pixel = std::mem::size_of::<u32>();
+    let unpadded_row_size = width as usize * bytes_per_pixel;
+    let mut pixels = Vec::with_capacity(unpadded_row_size * height as usize);
+    {
+        let padded_data = buffer_slice.get_mapped_range();
+        for row in padded_data.chunks(padded_row_size as usize) {
+            pixels.extend_from_slice(&row[..unpadded_row_size]);
+        }
+    }
+    buffer.unmap();
+
+    pixels
+}
+
+/// Runs `fut` to completion. Off wasm32 this blocks the calling thread via
+/// `smol`; on wasm32 there is no thread to block, so the future is handed
+/// to the browser's microtask queue instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn_task<F: std::future::Future<Output = ()>>(fut: F) {
+    smol::block_on(fut);
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn spawn_task<F: std::future::Future<Output = ()> + 'static>(fut: F) {
+    wasm_bindgen_futures::spawn_local(fut);
+}
+
+pub fn run(
+    event_loop: EventLoop<()>,
+    window: Arc<Window>,
+    swapchain_format: wgpu::TextureFormat,
+    config: RendererConfig,
+    #[cfg(not(feature = "spirv-shaders"))] wgsl_source: Cow<'static, str>,
+) {
+    let instance = gpu::create_instance(&config);
     let wgpucontext = WgpuContext {
         instance,
         stage: WgpuStage::Init,
+        config,
+        #[cfg(not(feature = "spirv-shaders"))]
+        wgsl_source,
     };
     let guard = Arc::new(Mutex::new(wgpucontext));
     let cloned_window = window.clone();
@@ -246,7 +807,7 @@ fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::T
             Event::NewEvents(StartCause::Init) => {
                 let mut cg1 = guard.clone();
                 let cw = cloned_window.clone();
-                let _t = smol::block_on(async move {
+                spawn_task(async move {
                     setup(
                         &mut cg1,
                         cw.clone(),
@@ -254,12 +815,12 @@ fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::T
                     )
                     .await;
                     println!("got StartCause::Init:");
-                });              
+                });
             }
             Event::Resumed => {
                 let mut cg1 = guard.clone();
                 let cw = cloned_window.clone();
-                let _t = smol::block_on(async move {
+                spawn_task(async move {
                     setup(
                         &mut cg1,
                         cw.clone(),
@@ -270,11 +831,11 @@ fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::T
                 });
             }
             Event::Suspended => {
-                // let mut cg1 = guard.clone();
-                // let _t = smol::block_on(async move {
-                //     clean_surface(&mut cg1).await;
-                //     println!("got Suspended");
-                // });
+                let mut cg1 = guard.clone();
+                spawn_task(async move {
+                    clean_surface(&mut cg1).await;
+                    println!("got Suspended");
+                });
             }
             Event::WindowEvent {
                 event: WindowEvent::Resized(size),
@@ -283,18 +844,19 @@ fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::T
                 println!("got Resized:");
                 // Recreate the swap chain with the new size
                 let cg1 = guard.clone();
-                let _t = smol::block_on(async move {
+                spawn_task(async move {
                     let context = cg1.clone();
                     let mut unlocked_context = context.lock().await;
-                    let ref mut ctx = *unlocked_context;
+                    let ctx = &mut *unlocked_context;
                     match ctx.stage {
                         WgpuStage::Ready(ref mut ready) => {
                             println!("got Resized Ready: \t {}", ready.surface.is_some());
                             if let Some(ref surface) = &ready.surface {
                                 ready.sc_desc.width = size.width;
                                 ready.sc_desc.height = size.height;
-                                ready.swap_chain =
-                                    ready.device.create_swap_chain(surface, &ready.sc_desc);
+                                ready.render_target = RenderTarget::SwapChainTarget(
+                                    gpu::recreate_swap_chain(&ready.device, surface, &ready.sc_desc),
+                                );
                                 println!("Resized:");
                             }
                         }
@@ -318,7 +880,7 @@ fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::T
                 println!("got RedrawRequested:");
                 let mut cg1 = guard.clone();
                 let cw = cloned_window.clone();
-                let _t = smol::block_on(async move {
+                spawn_task(async move {
                     setup(
                         &mut cg1,
                         cw.clone(),
@@ -341,7 +903,7 @@ fn run(event_loop: EventLoop<()>, window: Arc<Window>, swapchain_format: wgpu::T
 ndk_glue::ndk_glue!(main);
 
 // #[cfg_attr(target_os = "android", ndk_glue::main(backtrace = "on"))]
-fn main() {
+pub fn main() {
     // let _trace;
     // if trace::is_trace_enabled() {
     //     _trace = trace::Section::new("ndk-rs example main").unwrap();
@@ -350,14 +912,57 @@ fn main() {
 
     let event_loop = EventLoop::new();
     let window = winit::window::Window::new(&event_loop).unwrap();
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        // Attach the winit canvas to the DOM so the browser actually shows it,
+        // and route panics/logs through the console instead of stdout.
+        std::panic::set_hook(Box::new(console_error_panic_hook::hook));
+        console_log::init_with_level(log::Level::Info).expect("could not initialize logger");
+
+        use winit::platform::web::WindowExtWebSys;
+        web_sys::window()
+            .and_then(|win| win.document())
+            .and_then(|doc| doc.body())
+            .and_then(|body| {
+                body.append_child(&web_sys::Element::from(window.canvas()))
+                    .ok()
+            })
+            .expect("couldn't append canvas to document body");
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     {
-        subscriber::initialize_default_subscriber(None);
+        env_logger::init();
         // Temporarily avoid srgb formats for the swapchain on the web
         run(
             event_loop,
             Arc::new(window),
             wgpu::TextureFormat::Rgba8Unorm,
+            RendererConfig::default(),
+            #[cfg(not(feature = "spirv-shaders"))]
+            Cow::Borrowed(DEFAULT_WGSL_SHADER),
+        );
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        // winit's EventLoop::run() is already wasm-aware (it drives itself
+        // off requestAnimationFrame instead of blocking); the setup/draw
+        // futures inside it go through spawn_task, not smol::block_on.
+        run(
+            event_loop,
+            Arc::new(window),
+            wgpu::TextureFormat::Rgba8Unorm,
+            RendererConfig {
+                // On wasm32 this wgpu version drives only the browser's WebGPU
+                // backend (part of PRIMARY; there is no WebGL2 backend), so
+                // there is no real adapter choice.
+                backends: wgpu::BackendBit::PRIMARY,
+                ..RendererConfig::default()
+            },
+            #[cfg(not(feature = "spirv-shaders"))]
+            Cow::Borrowed(DEFAULT_WGSL_SHADER),
         );
     }
 }